@@ -1,6 +1,28 @@
+#![cfg_attr(not(any(test, feature = "std")), no_std)]
+#![feature(allocator_api)]
+
+extern crate alloc;
+
+// Shared generic implementation `sync`/`spin_sync` are thin aliases over,
+// so the two backends can't drift apart the way they once did.
+#[cfg(any(feature = "std", feature = "spin"))]
+mod sync_common;
+
+// The `std::sync`-backed implementation needs an actual OS mutex.
+#[cfg(feature = "std")]
 pub mod sync;
 
-use std::{alloc::Layout, cell::{Cell, RefCell}, ops::{Deref, DerefMut}};
+// A `no_std`-friendly sync backend built on a spin-lock instead.
+#[cfg(feature = "spin")]
+pub mod spin_sync;
+
+use alloc::boxed::Box;
+use core::{
+    alloc::{AllocError, Allocator, Layout},
+    cell::{Cell, RefCell},
+    ops::{Deref, DerefMut},
+    ptr::NonNull,
+};
 
 #[derive(Debug)]
 pub struct FixBufferedAllocator<'buf> {
@@ -19,14 +41,14 @@ impl<'buf> FixBufferedAllocator<'buf> {
 
     pub fn alloc_raw(&mut self, layout: Layout) -> *mut u8 {
         let Some(aligned_offset) = self.offset.checked_add(self.padding(layout.align())) else {
-            return std::ptr::null_mut();
+            return core::ptr::null_mut();
         };
 
         let Some(total) = aligned_offset.checked_add(layout.size()) else {
-            return std::ptr::null_mut();
+            return core::ptr::null_mut();
         };
 
-        if total > self.buf.len() { return std::ptr::null_mut(); }
+        if total > self.buf.len() { return core::ptr::null_mut(); }
 
         let ptr = unsafe {
             self.buf.as_mut_ptr().add(aligned_offset)
@@ -47,8 +69,8 @@ impl<'buf> FixBufferedAllocator<'buf> {
     }
 
     pub fn alloc_slice<T>(&mut self, length: usize) -> Option<&'buf mut [T]> {
-        let size = std::mem::size_of::<T>().checked_mul(length)?;
-        let align = std::mem::align_of::<T>();
+        let size = core::mem::size_of::<T>().checked_mul(length)?;
+        let align = core::mem::align_of::<T>();
         
         let layout = Layout::from_size_align(size, align).ok()?;
         let ptr = self.alloc_raw(layout) as *mut T;
@@ -56,7 +78,7 @@ impl<'buf> FixBufferedAllocator<'buf> {
         if ptr.is_null() {
             None
         } else {
-            Some(unsafe { std::slice::from_raw_parts_mut(ptr, length) })
+            Some(unsafe { core::slice::from_raw_parts_mut(ptr, length) })
         }
     }
 
@@ -65,23 +87,171 @@ impl<'buf> FixBufferedAllocator<'buf> {
             return Err(value);
         };
 
-        let res: &mut T = unsafe { std::mem::transmute(res) };
+        let res: &mut T = unsafe { core::mem::transmute(res) };
 
-        *res = value;
+        // `res` points at uninitialized memory, so write into it directly
+        // instead of `*res = value`, which would run `T`'s destructor on
+        // whatever garbage bytes were already there.
+        unsafe { core::ptr::write(res, value) };
         Ok(res)
     }
+
+    fn offset_of(&mut self, ptr: *mut u8) -> usize {
+        let base = self.buf.as_mut_ptr() as usize;
+        ptr as usize - base
+    }
+
+    /// Rolls `offset` back if `ptr`/`layout` describe exactly the most recent
+    /// allocation, reclaiming its space, and reports whether it did.
+    /// Otherwise does nothing: bump allocators can't reclaim the middle of
+    /// the buffer, so anything that isn't the tail allocation is left alone.
+    ///
+    /// # Safety
+    ///
+    /// `ptr`/`layout` must exactly describe an allocation this allocator
+    /// handed back and that is still outstanding (no other live reference
+    /// into it). Passing a `ptr`/`layout` that merely *happens* to match the
+    /// current tail by coincidence reclaims real, still-referenced memory
+    /// and hands it out again on the next allocation, aliasing it.
+    pub unsafe fn free_last(&mut self, ptr: *mut u8, layout: Layout) -> bool {
+        if layout.size() == 0 {
+            return false;
+        }
+
+        let start = self.offset_of(ptr);
+
+        if start + layout.size() != self.offset {
+            return false;
+        }
+
+        self.offset = start;
+        true
+    }
+
+    /// Extends the most recent allocation in place when there's room,
+    /// without touching anything else in the buffer. Reports whether it
+    /// could: a stale `ptr`/`old_layout` (not the tail allocation), a
+    /// mismatched alignment, or a buffer that's simply full all fail.
+    ///
+    /// # Safety
+    ///
+    /// `ptr`/`old_layout` must exactly describe an allocation this allocator
+    /// handed back and that is still outstanding, same as [`Self::free_last`].
+    pub unsafe fn grow_last(&mut self, ptr: *mut u8, old_layout: Layout, new_layout: Layout) -> bool {
+        if new_layout.align() != old_layout.align() {
+            return false;
+        }
+
+        let start = self.offset_of(ptr);
+
+        if start + old_layout.size() != self.offset {
+            return false;
+        }
+
+        let Some(new_end) = start.checked_add(new_layout.size()) else {
+            return false;
+        };
+
+        if new_end > self.buf.len() {
+            return false;
+        }
+
+        self.offset = new_end;
+        true
+    }
+
+    /// Shrinks the most recent allocation in place, giving the freed tail
+    /// back to the buffer, and reports whether `ptr`/`old_layout` was in
+    /// fact the tail allocation. Shrinking never runs out of room, so this
+    /// only fails when `ptr` doesn't describe the most recent allocation or
+    /// `new_layout` is bigger than `old_layout` (an equal size succeeds).
+    ///
+    /// # Safety
+    ///
+    /// `ptr`/`old_layout` must exactly describe an allocation this allocator
+    /// handed back and that is still outstanding, same as [`Self::free_last`].
+    pub unsafe fn shrink_last(&mut self, ptr: *mut u8, old_layout: Layout, new_layout: Layout) -> bool {
+        if new_layout.size() > old_layout.size() {
+            return false;
+        }
+
+        let start = self.offset_of(ptr);
+
+        if start + old_layout.size() != self.offset {
+            return false;
+        }
+
+        self.offset = start + new_layout.size();
+        true
+    }
+}
+
+// Thunk + pointer pair describing how to drop a value that was placed with
+// `create_with_drop`. Nodes are themselves bump-allocated right next to the
+// value they describe and linked through `prev`, forming an intrusive,
+// most-recent-first stack that's walked on `restart`/`new_buffer`.
+struct DropNode {
+    drop_fn: unsafe fn(*mut u8),
+    data: *mut u8,
+    prev: Option<NonNull<DropNode>>,
+}
+
+unsafe fn drop_in_place_thunk<T>(ptr: *mut u8) {
+    unsafe { core::ptr::drop_in_place(ptr as *mut T) };
+}
+
+// Bookkeeping for one active `mark()` scope: how many live references were
+// created while it was the innermost marker, and the marker it nests under.
+// Heap-allocated (not bump-allocated) since its lifetime tracks the scope,
+// not the buffer offset, and it must survive a `reset_to` of any marker
+// nested inside it.
+struct MarkerNode {
+    offset: usize,
+    live_count: Cell<usize>,
+    prev: Option<NonNull<MarkerNode>>,
+}
+
+// A snapshot taken by `mark()`, consumed by `reset_to`/`try_reset_to`.
+// Markers nest like a stack: only the innermost one still active can be
+// reset, which is what makes a plain per-marker live count sound (a
+// reference only ever counts against whichever marker was innermost when
+// it was created).
+//
+// Deliberately not `Clone`/`Copy`: `reset_to`/`try_reset_to` free the
+// `MarkerNode` a `Marker` points at on success, and nothing stops the
+// allocator from handing that same address back to a later `mark()` call.
+// A `Copy`able `Marker` kept past its `reset_to` could then be replayed
+// against that unrelated, currently-active marker purely because the
+// pointer happened to match (an ABA bug, the same class `dbdce7f` fixed
+// for the old counter-snapshot design). Taking `Marker` by value forces
+// the one real copy to be consumed by the `reset_to` call that spends it,
+// so the compiler rejects any attempt to reuse it.
+#[derive(Debug)]
+pub struct Marker {
+    node: NonNull<MarkerNode>,
 }
 
 #[derive(Debug)]
 pub struct RestartableFBA<'buf> {
     alloc: RefCell<FixBufferedAllocator<'buf>>,
     counter: Cell<usize>,
+    drop_head: Cell<Option<NonNull<DropNode>>>,
+    marker_head: Cell<Option<NonNull<MarkerNode>>>,
+    // Number of allocations currently outstanding through the `Allocator`
+    // impl below (`Vec::new_in`/`Box::new_in` and friends). These aren't
+    // wrapped in an `AllocatedRef`, so they can't bump `counter` or a
+    // specific marker's `live_count` the way `create`/`alloc` do. Instead
+    // `restart`/`new_buffer`/`reset_to` all refuse to run while this is
+    // nonzero, conservatively treating any outstanding `Allocator`-issued
+    // block as if it might alias the region being reclaimed.
+    raw_alloc_counter: Cell<usize>,
 }
 
 #[derive(Debug)]
 pub struct AllocatedRef<'a, T: ?Sized> {
     reference: &'a mut T,
     allocator: &'a RestartableFBA<'a>,
+    scope: Option<NonNull<MarkerNode>>,
 }
 
 impl<'a, T: ?Sized> Deref for AllocatedRef<'a, T> {
@@ -103,6 +273,13 @@ impl<'a, T: ?Sized> Drop for AllocatedRef<'a, T> {
         let counter = self.allocator.counter.get();
         assert!(counter >= 1);
         self.allocator.counter.set(counter - 1);
+
+        if let Some(node) = self.scope {
+            let node = unsafe { node.as_ref() };
+            let live = node.live_count.get();
+            assert!(live >= 1);
+            node.live_count.set(live - 1);
+        }
     }
 }
 
@@ -110,26 +287,42 @@ impl<'buf> RestartableFBA<'buf> {
     pub fn new(buf: &'buf mut [u8]) -> Self {
         Self {
             alloc: RefCell::new(FixBufferedAllocator::new(buf)),
-            counter: Cell::new(0)
+            counter: Cell::new(0),
+            drop_head: Cell::new(None),
+            marker_head: Cell::new(None),
+            raw_alloc_counter: Cell::new(0),
         }
     }
 
+    // Records the marker (if any) a just-created reference counts against,
+    // bumping its live count so `try_reset_to` can tell it's still live.
+    fn track_scope(&self) -> Option<NonNull<MarkerNode>> {
+        let scope = self.marker_head.get();
+        if let Some(node) = scope {
+            let node = unsafe { node.as_ref() };
+            node.live_count.set(node.live_count.get() + 1);
+        }
+        scope
+    }
+
     pub fn alloc<'alloc: 'buf>(&'alloc self, layout: Layout) -> Option<AllocatedRef<'buf, u8>> {
         let r = self.alloc.borrow_mut().alloc(layout)?;
 
         let counter = self.counter.get();
         self.counter.set(counter + 1);
+        let scope = self.track_scope();
 
-        Some(AllocatedRef { reference: r, allocator: self })
-    }    
+        Some(AllocatedRef { reference: r, allocator: self, scope })
+    }
 
     pub fn alloc_slice<'alloc: 'buf, T>(&'alloc self, length: usize) -> Option<AllocatedRef<'buf, [T]>> {
         let s = self.alloc.borrow_mut().alloc_slice::<T>(length)?;
 
         let counter = self.counter.get();
         self.counter.set(counter + 1);
+        let scope = self.track_scope();
 
-        Some(AllocatedRef { reference: s, allocator: self })
+        Some(AllocatedRef { reference: s, allocator: self, scope })
     }
 
     pub fn create<'alloc: 'buf, T>(&'alloc self, value: T) -> Result<AllocatedRef<'buf, T>, T> {
@@ -137,8 +330,107 @@ impl<'buf> RestartableFBA<'buf> {
 
         let counter = self.counter.get();
         self.counter.set(counter + 1);
+        let scope = self.track_scope();
+
+        Ok(AllocatedRef { reference: r, allocator: self, scope })
+    }
+
+    /// Like [`Self::create`], but if `T` has drop glue, also bump-allocates a
+    /// small destructor thunk right next to the value and links it into an
+    /// intrusive list, so the value's `Drop` impl runs in reverse order on
+    /// `restart`/`new_buffer`. `T`s without drop glue (e.g. `Copy`/POD types)
+    /// skip the thunk entirely and cost exactly what `create` costs.
+    ///
+    /// `buf` is a borrow rather than memory this allocator owns, so unlike
+    /// `restart`/`new_buffer` there's no point in the allocator's own `Drop`
+    /// where running destructors would be meaningful: if you need them to
+    /// run deterministically, call `restart()` before letting the allocator
+    /// go out of scope.
+    ///
+    /// `alloc`/`alloc_slice` hand back uninitialized memory rather than a
+    /// constructed value, so there's nothing for this to hook into there;
+    /// only values placed with `create`/`create_with_drop` are tracked.
+    pub fn create_with_drop<'alloc: 'buf, T>(&'alloc self, value: T) -> Result<AllocatedRef<'buf, T>, T> {
+        if !core::mem::needs_drop::<T>() {
+            return self.create(value);
+        }
+
+        let mut alloc = self.alloc.borrow_mut();
+        let start_offset = alloc.offset;
+
+        let Some(value_ref) = alloc.alloc(Layout::new::<T>()) else {
+            return Err(value);
+        };
+        let value_ref: &'buf mut T = unsafe { core::mem::transmute(value_ref) };
+
+        let node = DropNode {
+            drop_fn: drop_in_place_thunk::<T>,
+            data: value_ref as *mut T as *mut u8,
+            prev: self.drop_head.get(),
+        };
+
+        let Ok(node_ref) = alloc.create(node) else {
+            // Couldn't reserve room for the thunk right after reserving room
+            // for the value itself; give the space back and fail like
+            // `create` does. `value` hasn't been written into the buffer
+            // yet, so there's nothing to drop.
+            alloc.offset = start_offset;
+            return Err(value);
+        };
+
+        // As in `create`, `value_ref` points at uninitialized memory: write
+        // into it instead of assigning, which would try to drop whatever
+        // garbage was already there.
+        unsafe { core::ptr::write(value_ref, value) };
+        self.drop_head.set(NonNull::new(node_ref as *mut DropNode));
+
+        drop(alloc);
+
+        let counter = self.counter.get();
+        self.counter.set(counter + 1);
+        let scope = self.track_scope();
 
-        Ok(AllocatedRef { reference: r, allocator: self })
+        Ok(AllocatedRef { reference: value_ref, allocator: self, scope })
+    }
+
+    // Runs every tracked destructor thunk in most-recent-first order and
+    // clears the list. Called whenever the buffer is about to be reset.
+    fn run_destructors(&self) {
+        self.run_destructors_since(0);
+    }
+
+    // Runs tracked destructor thunks for values placed at or after `offset`,
+    // most-recent-first, and leaves the list pointing at whatever (if
+    // anything) was placed before `offset`. Called whenever the bump pointer
+    // is about to roll back, whether all the way to 0 (`restart`) or to a
+    // `Marker` (`reset_to`).
+    fn run_destructors_since(&self, offset: usize) {
+        let base = self.alloc.borrow().buf.as_ptr() as usize;
+        let mut head = self.drop_head.get();
+
+        while let Some(node_ptr) = head {
+            let node = unsafe { node_ptr.as_ref() };
+            if (node.data as usize - base) < offset {
+                break;
+            }
+            unsafe { (node.drop_fn)(node.data) };
+            head = node.prev;
+        }
+
+        self.drop_head.set(head);
+    }
+
+    // Drops every still-active marker node. Only called once `counter == 0`
+    // has confirmed there are no live references left anywhere, which means
+    // every marker's `live_count` is already 0 too, so this can't orphan a
+    // reference that still thinks it belongs to a freed marker.
+    fn clear_markers(&self) {
+        let mut head = self.marker_head.take();
+
+        while let Some(node) = head {
+            let node = unsafe { Box::from_raw(node.as_ptr()) };
+            head = node.prev;
+        }
     }
 
     pub fn restart(&self) {
@@ -150,18 +442,22 @@ impl<'buf> RestartableFBA<'buf> {
     }
 
     pub fn try_restard(&self) -> Option<()> {
-        if self.counter.get() != 0 {
+        if self.counter.get() != 0 || self.raw_alloc_counter.get() != 0 {
             None
         } else {
+            self.run_destructors();
+            self.clear_markers();
             self.alloc.borrow_mut().offset = 0;
             Some(())
         }
     }
 
     pub fn try_new_buffer(&self, buf: &'buf mut [u8]) -> Option<()> {
-        if self.counter.get() != 0 {
+        if self.counter.get() != 0 || self.raw_alloc_counter.get() != 0 {
             None
         } else {
+            self.run_destructors();
+            self.clear_markers();
             let mut alloc = self.alloc.borrow_mut();
             alloc.buf = buf;
             alloc.offset = 0;
@@ -169,8 +465,62 @@ impl<'buf> RestartableFBA<'buf> {
         }
     }
 
+    // Pushes a new scope node snapshotting the current offset, so
+    // `reset_to`/`try_reset_to` can later roll back to it. Markers nest like
+    // a stack: only the current innermost one (`marker_head`) can be reset,
+    // which is what makes its own `live_count` (references created while it,
+    // specifically, was innermost) a sound thing to check in isolation.
+    pub fn mark(&self) -> Marker {
+        let node = Box::new(MarkerNode {
+            offset: self.alloc.borrow().offset,
+            live_count: Cell::new(0),
+            prev: self.marker_head.get(),
+        });
+        let node = NonNull::from(Box::leak(node));
+        self.marker_head.set(Some(node));
+        Marker { node }
+    }
+
+    pub fn reset_to(&self, marker: Marker) {
+        self.try_reset_to(marker).expect(
+            "Marker can be reset only when it's the innermost active marker and has no references created since it",
+        )
+    }
+
+    // Only succeeds for the current innermost marker (anything else would
+    // mean a more-nested marker, and the references created under it, are
+    // still outstanding) and only once nothing created under it is live.
+    // Also refused while any `Allocator`-issued block is outstanding
+    // anywhere in the buffer: those aren't attributed to a specific marker
+    // (see `raw_alloc_counter`), so there's no way to tell whether one of
+    // them lives in the region this would roll back over.
+    //
+    // On failure `marker` is handed back rather than dropped, so a caller
+    // whose reset was premature (a live reference it forgot about, an inner
+    // marker it hasn't reset yet) can drop that reference/marker and retry
+    // with the same `Marker` instead of losing the scope for good.
+    pub fn try_reset_to(&self, marker: Marker) -> Result<(), Marker> {
+        if self.marker_head.get() != Some(marker.node) {
+            return Err(marker);
+        }
+
+        let node = unsafe { marker.node.as_ref() };
+        if node.live_count.get() != 0 || self.raw_alloc_counter.get() != 0 {
+            return Err(marker);
+        }
+
+        let offset = node.offset;
+        self.marker_head.set(node.prev);
+
+        self.run_destructors_since(offset);
+        self.alloc.borrow_mut().offset = offset;
+        drop(unsafe { Box::from_raw(marker.node.as_ptr()) });
+
+        Ok(())
+    }
+
     pub fn get_buf(&self) -> Option<&'buf mut [u8]> {
-        if self.counter.get() != 0 {
+        if self.counter.get() != 0 || self.raw_alloc_counter.get() != 0 {
             None
         } else {
             unsafe {
@@ -178,12 +528,190 @@ impl<'buf> RestartableFBA<'buf> {
             }
         }
     }
+
+    /// Releases `value`, reclaiming its memory when it's the most recent
+    /// allocation still outstanding (see [`FixBufferedAllocator::free_last`])
+    /// and always giving up its hold on the live-reference counter, same as
+    /// letting it drop would. Reports whether the memory was reclaimed.
+    ///
+    /// A value with drop glue placed with [`Self::create_with_drop`] is followed
+    /// by its own destructor thunk, so it's never the tail allocation by the time you'd
+    /// call this: the reference is released either way, but its destructor
+    /// only runs on the next `restart`/`reset_to`, same as normally dropping it.
+    pub fn free_last<T: ?Sized>(&self, value: AllocatedRef<'buf, T>) -> bool {
+        let layout = Layout::for_value::<T>(&value);
+        let ptr = value.reference as *mut T as *mut u8;
+        let scope = value.scope;
+
+        // Safety: `value` is an owned `AllocatedRef` this allocator itself
+        // handed back, so `ptr`/`layout` exactly describe one of its own
+        // still-outstanding allocations.
+        let freed = unsafe { self.alloc.borrow_mut().free_last(ptr, layout) };
+        if freed {
+            core::mem::forget(value);
+
+            let counter = self.counter.get();
+            assert!(counter >= 1);
+            self.counter.set(counter - 1);
+
+            if let Some(node) = scope {
+                let node = unsafe { node.as_ref() };
+                let live = node.live_count.get();
+                assert!(live >= 1);
+                node.live_count.set(live - 1);
+            }
+        }
+
+        freed
+    }
+}
+
+// Lets `RestartableFBA` back `Vec::new_in`/`Box::new_in`/`String::new_in` and
+// friends directly, bypassing the bespoke `create`/`alloc_slice` API. This is
+// a bump allocator, so `deallocate` only has an effect when freeing the most
+// recent allocation, and `grow` extends in place under the same condition.
+// `allocate`/`deallocate` bump/drop `raw_alloc_counter` so that a `Vec`/`Box`
+// built this way keeps `restart`/`new_buffer`/`reset_to` from rolling the
+// buffer back underneath it, the same guarantee `counter` gives `AllocatedRef`.
+unsafe impl<'buf> Allocator for RestartableFBA<'buf> {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let ptr = self.alloc.borrow_mut().alloc_raw(layout);
+        let ptr = NonNull::new(ptr).ok_or(AllocError)?;
+
+        self.raw_alloc_counter.set(self.raw_alloc_counter.get() + 1);
+
+        Ok(NonNull::slice_from_raw_parts(ptr, layout.size()))
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        unsafe { self.alloc.borrow_mut().free_last(ptr.as_ptr(), layout) };
+
+        let count = self.raw_alloc_counter.get();
+        assert!(count >= 1);
+        self.raw_alloc_counter.set(count - 1);
+    }
+
+    unsafe fn grow(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        debug_assert!(new_layout.size() >= old_layout.size());
+
+        let grew_in_place = unsafe {
+            self.alloc
+                .borrow_mut()
+                .grow_last(ptr.as_ptr(), old_layout, new_layout)
+        };
+
+        if grew_in_place {
+            return Ok(NonNull::slice_from_raw_parts(ptr, new_layout.size()));
+        }
+
+        let new_ptr = self.allocate(new_layout)?;
+        unsafe {
+            core::ptr::copy_nonoverlapping(ptr.as_ptr(), new_ptr.as_ptr() as *mut u8, old_layout.size());
+            self.deallocate(ptr, old_layout);
+        }
+
+        Ok(new_ptr)
+    }
+
+    unsafe fn shrink(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        debug_assert!(new_layout.size() <= old_layout.size());
+
+        unsafe {
+            self.alloc
+                .borrow_mut()
+                .shrink_last(ptr.as_ptr(), old_layout, new_layout)
+        };
+
+        Ok(NonNull::slice_from_raw_parts(ptr, new_layout.size()))
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn grow_last_extends_tail_allocation_in_place() {
+        let mut b = [0u8; 8];
+        let mut a = FixBufferedAllocator::new(&mut b);
+
+        let s = a.alloc_slice::<u8>(2).unwrap();
+        let ptr = s.as_mut_ptr();
+
+        assert!(unsafe { a.grow_last(ptr, Layout::array::<u8>(2).unwrap(), Layout::array::<u8>(4).unwrap()) });
+        assert_eq!(a.offset, 4);
+
+        // Not the tail allocation anymore: the buffer has moved on.
+        let _ = a.alloc_slice::<u8>(1).unwrap();
+        assert!(!unsafe { a.grow_last(ptr, Layout::array::<u8>(4).unwrap(), Layout::array::<u8>(6).unwrap()) });
+    }
+
+    #[test]
+    fn shrink_last_gives_back_the_tail() {
+        let mut b = [0u8; 8];
+        let mut a = FixBufferedAllocator::new(&mut b);
+
+        let s = a.alloc_slice::<u8>(4).unwrap();
+        let ptr = s.as_mut_ptr();
+
+        assert!(unsafe { a.shrink_last(ptr, Layout::array::<u8>(4).unwrap(), Layout::array::<u8>(1).unwrap()) });
+        assert_eq!(a.offset, 1);
+    }
+
+    #[test]
+    fn shrink_last_rejects_a_new_layout_that_is_actually_bigger() {
+        let mut b = [0u8; 8];
+        let mut a = FixBufferedAllocator::new(&mut b);
+
+        let s = a.alloc_slice::<u8>(2).unwrap();
+        let ptr = s.as_mut_ptr();
+
+        assert!(!unsafe { a.shrink_last(ptr, Layout::array::<u8>(2).unwrap(), Layout::array::<u8>(4).unwrap()) });
+        assert_eq!(a.offset, 2);
+    }
+
+    #[test]
+    fn free_last_reclaims_only_the_tail_allocation() {
+        let mut b = [0u8; 8];
+        let mut a = FixBufferedAllocator::new(&mut b);
+
+        let v1 = a.create(1u8).unwrap();
+        let ptr1 = v1 as *mut u8;
+        let v2 = a.create(2u8).unwrap();
+        let ptr2 = v2 as *mut u8;
+
+        assert!(!unsafe { a.free_last(ptr1, Layout::new::<u8>()) });
+        assert_eq!(a.offset, 2);
+
+        assert!(unsafe { a.free_last(ptr2, Layout::new::<u8>()) });
+        assert_eq!(a.offset, 1);
+    }
+
+    #[test]
+    fn restartable_free_last_reclaims_space_and_releases_the_counter() {
+        let mut b = [0u8; 8];
+        let a = RestartableFBA::new(&mut b);
+
+        let v1 = a.create(1u8).unwrap();
+        assert!(a.free_last(v1));
+
+        // Reclaimed space and released the counter, so a full-width
+        // allocation and a restart both succeed right away.
+        let v2 = a.create([0u8; 8]).unwrap();
+        assert!(a.free_last(v2));
+        a.restart();
+    }
+
     #[test]
     fn it_works() {
         let mut b = [0u8; 5];
@@ -237,7 +765,7 @@ mod tests {
         dbg!(&a);
         let s: &mut [u8] = a.alloc_slice(5).unwrap();
         s.clone_from_slice("Hello".as_bytes());
-        let s: &mut str = unsafe {std::mem::transmute(s)};
+        let s: &mut str = core::str::from_utf8_mut(s).unwrap();
         dbg!(&a, s);
     }
 
@@ -275,6 +803,203 @@ mod tests {
         assert_eq!(&b, &[255]);
     }
 
+    #[test]
+    fn allocator_api_vec_new_in() {
+        let mut b = [0u8; 64];
+        let a = RestartableFBA::new(&mut b);
+
+        let mut v: Vec<u8, _> = Vec::new_in(&a);
+        for i in 0..10u8 {
+            v.push(i);
+        }
+
+        dbg!(&a, &v);
+        assert_eq!(v.as_slice(), &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9]);
+    }
+
+    #[test]
+    fn create_with_drop_runs_on_restart_not_on_ref_drop() {
+        use std::{cell::RefCell, rc::Rc};
+
+        #[derive(Debug)]
+        struct DropCounter(Rc<RefCell<u32>>);
+        impl Drop for DropCounter {
+            fn drop(&mut self) {
+                *self.0.borrow_mut() += 1;
+            }
+        }
+
+        let count = Rc::new(RefCell::new(0));
+        let mut b = [0u8; 64];
+        let a = RestartableFBA::new(&mut b);
+
+        {
+            let _v = a.create_with_drop(DropCounter(Rc::clone(&count))).unwrap();
+            assert_eq!(*count.borrow(), 0);
+        }
+
+        assert_eq!(*count.borrow(), 0, "dropping the AllocatedRef alone must not run T's destructor");
+        a.restart();
+        assert_eq!(*count.borrow(), 1);
+    }
+
+    #[test]
+    fn create_with_drop_runs_in_reverse_order() {
+        use std::{cell::RefCell, rc::Rc};
+
+        #[derive(Debug)]
+        struct Tag(u32, Rc<RefCell<Vec<u32>>>);
+        impl Drop for Tag {
+            fn drop(&mut self) {
+                self.1.borrow_mut().push(self.0);
+            }
+        }
+
+        let order = Rc::new(RefCell::new(Vec::new()));
+        let mut b = [0u8; 128];
+        let a = RestartableFBA::new(&mut b);
+
+        {
+            let _v1 = a.create_with_drop(Tag(1, Rc::clone(&order))).unwrap();
+            let _v2 = a.create_with_drop(Tag(2, Rc::clone(&order))).unwrap();
+            let _v3 = a.create_with_drop(Tag(3, Rc::clone(&order))).unwrap();
+        }
+
+        a.restart();
+        assert_eq!(*order.borrow(), vec![3, 2, 1]);
+    }
+
+    #[test]
+    fn create_with_drop_is_free_for_copy_types() {
+        let mut b = [0u8; 2];
+        let a = RestartableFBA::new(&mut b);
+
+        let v1 = a.create_with_drop(1u8).unwrap();
+        let v2 = a.create_with_drop(2u8).unwrap();
+
+        assert_eq!(*v1, 1);
+        assert_eq!(*v2, 2);
+    }
+
+    #[test]
+    fn reset_to_frees_everything_after_the_marker() {
+        let mut b = [0u8; 64];
+        let a = RestartableFBA::new(&mut b);
+
+        let _v1 = a.create(1u8).unwrap();
+        let marker = a.mark();
+        {
+            let _v2 = a.create(2u8).unwrap();
+            let _v3 = a.create(3u8).unwrap();
+        }
+        a.reset_to(marker);
+
+        let v4 = a.create(4u8).unwrap();
+        assert_eq!(*v4, 4);
+    }
+
+    #[test]
+    fn reset_to_runs_destructors_for_values_after_marker_only() {
+        use std::{cell::RefCell, rc::Rc};
+
+        #[derive(Debug)]
+        struct Tag(u32, Rc<RefCell<Vec<u32>>>);
+        impl Drop for Tag {
+            fn drop(&mut self) {
+                self.1.borrow_mut().push(self.0);
+            }
+        }
+
+        let order = Rc::new(RefCell::new(Vec::new()));
+        let mut b = [0u8; 128];
+        let a = RestartableFBA::new(&mut b);
+
+        {
+            let _v1 = a.create_with_drop(Tag(1, Rc::clone(&order))).unwrap();
+            let marker = a.mark();
+            {
+                let _v2 = a.create_with_drop(Tag(2, Rc::clone(&order))).unwrap();
+                let _v3 = a.create_with_drop(Tag(3, Rc::clone(&order))).unwrap();
+            }
+            a.reset_to(marker);
+            assert_eq!(*order.borrow(), vec![3, 2]);
+        }
+
+        a.restart();
+        assert_eq!(*order.borrow(), vec![3, 2, 1]);
+    }
+
+    #[test]
+    fn try_reset_to_fails_with_live_references_after_marker() {
+        let mut b = [0u8; 16];
+        let a = RestartableFBA::new(&mut b);
+
+        let marker = a.mark();
+        let _v1 = a.create(1u8).unwrap();
+
+        assert!(a.try_reset_to(marker).is_err());
+    }
+
+    #[test]
+    fn try_reset_to_returns_the_marker_so_a_dropped_reference_can_retry() {
+        let mut b = [0u8; 16];
+        let a = RestartableFBA::new(&mut b);
+
+        let marker = a.mark();
+        let v1 = a.create(1u8).unwrap();
+
+        let marker = a.try_reset_to(marker).unwrap_err();
+        drop(v1);
+
+        assert!(a.try_reset_to(marker).is_ok());
+        let v2 = a.create(2u8).unwrap();
+        assert_eq!(*v2, 2);
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "Marker can be reset only when it's the innermost active marker and has no references created since it"
+    )]
+    fn reset_to_panics_with_live_references_after_marker() {
+        let mut b = [0u8; 16];
+        let a = RestartableFBA::new(&mut b);
+
+        let marker = a.mark();
+        let _v1 = a.create(1u8).unwrap();
+        a.reset_to(marker); // This should panic
+    }
+
+    #[test]
+    fn nested_markers_resolve_innermost_first() {
+        let mut b = [0u8; 64];
+        let a = RestartableFBA::new(&mut b);
+
+        let outer = a.mark();
+        {
+            let _v1 = a.create(1u8).unwrap();
+            let inner = a.mark();
+            {
+                let _v2 = a.create(2u8).unwrap();
+            }
+            a.reset_to(inner);
+        }
+        a.reset_to(outer);
+
+        let v3 = a.create(3u8).unwrap();
+        assert_eq!(*v3, 3);
+    }
+
+    #[test]
+    fn outer_marker_cannot_be_reset_while_inner_marker_is_still_active() {
+        let mut b = [0u8; 64];
+        let a = RestartableFBA::new(&mut b);
+
+        let outer = a.mark();
+        let _inner = a.mark();
+
+        assert!(a.try_reset_to(outer).is_err());
+    }
+
     #[test]
     #[should_panic]
     fn get_buf_test_panic() {
@@ -0,0 +1,326 @@
+// `spin::Mutex`-backed scoped-reclamation allocator: same API as the
+// crate-root `RestartableFBA`, but thread-safe and `no_std`-friendly,
+// trading the OS mutex for a spin-lock. See `crate::sync_common` for the
+// shared implementation this and `crate::sync` are both generated from.
+use spin::Mutex;
+
+pub type RestartableFBA<'buf> = crate::sync_common::RestartableFBA<'buf, Mutex<crate::sync_common::State<'buf>>>;
+pub type AllocatedRef<'a, T> = crate::sync_common::AllocatedRef<'a, T>;
+pub type Marker = crate::sync_common::Marker;
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use alloc::sync::Arc;
+
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        let mut b = [0u8; 5];
+        let a = RestartableFBA::new(&mut b);
+        dbg!(&a);
+        let v1 = a.create(0x04030201).unwrap();
+        let v2 = a.create(0xffu8).unwrap();
+
+        assert_eq!(*v1, 0x04030201);
+        assert_eq!(*v2, 0xff);
+        dbg!(&a, v1, v2);
+    }
+
+    #[test]
+    fn it_works1() {
+        let mut buf = vec![0u8; 5];
+        let a = RestartableFBA::new(&mut buf);
+
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                a.create(0x0201u16).unwrap();
+                dbg!(&a);
+            });
+
+            scope.spawn(|| {
+                a.create(0x0403u16).unwrap();
+                dbg!(&a);
+            });
+        });
+
+        a.create(5u8).unwrap();
+        dbg!(&a);
+
+        let mut buf2 = [0u8; 1];
+        a.new_buffer(&mut buf2);
+        let v = a.create(!0u8).unwrap();
+        dbg!(&a);
+        assert_eq!(*v, !0u8);
+    }
+
+    #[test]
+    #[should_panic(expected = "Allocator can be restared only when there is no references to it's buffer and buffer is not borrowed")]
+    fn it_works2() {
+        let mut buf = vec![0u8; 5];
+        let a = RestartableFBA::new(&mut buf);
+
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                a.create(0x0201u16)
+            });
+
+            scope.spawn(|| {
+                a.create(0x0403u16)
+            });
+        });
+
+        let v = a.create(5u8).unwrap();
+        assert_eq!(*v, 5);
+        dbg!(&a);
+
+        a.restart();
+    }
+
+    #[test]
+    fn sync_get_buf_safe() {
+        let mut buf = [0u8; 4];
+        let alloc = Arc::new(RestartableFBA::new(&mut buf));
+
+        {
+            let _x = alloc.create(0x42u8).unwrap();
+        }
+
+
+        let mut buf_ref = alloc.get_buf().unwrap();
+        buf_ref[0] = 0xAA;
+
+        assert_eq!(&*buf_ref, &[0xAA, 0, 0, 0]);
+    }
+
+    #[test]
+    fn sync_get_buf_with_active_refs() {
+        let mut buf = [0u8; 4];
+        let alloc = Arc::new(RestartableFBA::new(&mut buf));
+
+        let _x = alloc.create(0x42u8).unwrap();
+        assert!(alloc.get_buf().is_none());
+    }
+
+    #[test]
+    fn allocator_api_vec_new_in() {
+        let mut buf = [0u8; 64];
+        let a = RestartableFBA::new(&mut buf);
+
+        let mut v: Vec<u8, _> = Vec::new_in(&a);
+        for i in 0..10u8 {
+            v.push(i);
+        }
+
+        dbg!(&a, &v);
+        assert_eq!(v.as_slice(), &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9]);
+    }
+
+    #[test]
+    fn it_works4() {
+        let mut buf = [0u8; 4];
+        let alloc = Arc::new(RestartableFBA::new(&mut buf));
+
+        {
+            let _ = alloc.create(0xfcfdfeffu32).unwrap();
+            dbg!(&alloc);
+        }
+
+        let b = alloc.get_buf().unwrap();
+        assert!(alloc.create(0x04030201u32).is_err());
+        dbg!(&b, &alloc);
+
+        drop(b);
+        alloc.restart();
+
+        let x = alloc.create(0x04030201u32).unwrap();
+        assert_eq!(*x, 0x04030201)
+    }
+
+    #[test]
+    fn create_with_drop_runs_on_restart_not_on_ref_drop() {
+        use std::{cell::RefCell, rc::Rc};
+
+        #[derive(Debug)]
+        struct DropCounter(Rc<RefCell<u32>>);
+        impl Drop for DropCounter {
+            fn drop(&mut self) {
+                *self.0.borrow_mut() += 1;
+            }
+        }
+
+        let count = Rc::new(RefCell::new(0));
+        let mut b = [0u8; 64];
+        let a = RestartableFBA::new(&mut b);
+
+        {
+            let _v = a.create_with_drop(DropCounter(Rc::clone(&count))).unwrap();
+            assert_eq!(*count.borrow(), 0);
+        }
+
+        assert_eq!(*count.borrow(), 0, "dropping the AllocatedRef alone must not run T's destructor");
+        a.restart();
+        assert_eq!(*count.borrow(), 1);
+    }
+
+    #[test]
+    fn create_with_drop_runs_in_reverse_order() {
+        use std::{cell::RefCell, rc::Rc};
+
+        #[derive(Debug)]
+        struct Tag(u32, Rc<RefCell<Vec<u32>>>);
+        impl Drop for Tag {
+            fn drop(&mut self) {
+                self.1.borrow_mut().push(self.0);
+            }
+        }
+
+        let order = Rc::new(RefCell::new(Vec::new()));
+        let mut b = [0u8; 128];
+        let a = RestartableFBA::new(&mut b);
+
+        {
+            let _v1 = a.create_with_drop(Tag(1, Rc::clone(&order))).unwrap();
+            let _v2 = a.create_with_drop(Tag(2, Rc::clone(&order))).unwrap();
+            let _v3 = a.create_with_drop(Tag(3, Rc::clone(&order))).unwrap();
+        }
+
+        a.restart();
+        assert_eq!(*order.borrow(), vec![3, 2, 1]);
+    }
+
+    #[test]
+    fn create_with_drop_is_free_for_copy_types() {
+        let mut b = [0u8; 2];
+        let a = RestartableFBA::new(&mut b);
+
+        let v1 = a.create_with_drop(1u8).unwrap();
+        let v2 = a.create_with_drop(2u8).unwrap();
+
+        assert_eq!(*v1, 1);
+        assert_eq!(*v2, 2);
+    }
+
+    #[test]
+    fn reset_to_frees_everything_after_the_marker() {
+        let mut b = [0u8; 64];
+        let a = RestartableFBA::new(&mut b);
+
+        let _v1 = a.create(1u8).unwrap();
+        let marker = a.mark();
+        {
+            let _v2 = a.create(2u8).unwrap();
+            let _v3 = a.create(3u8).unwrap();
+        }
+        a.reset_to(marker);
+
+        let v4 = a.create(4u8).unwrap();
+        assert_eq!(*v4, 4);
+    }
+
+    #[test]
+    fn reset_to_runs_destructors_for_values_after_marker_only() {
+        use std::{cell::RefCell, rc::Rc};
+
+        #[derive(Debug)]
+        struct Tag(u32, Rc<RefCell<Vec<u32>>>);
+        impl Drop for Tag {
+            fn drop(&mut self) {
+                self.1.borrow_mut().push(self.0);
+            }
+        }
+
+        let order = Rc::new(RefCell::new(Vec::new()));
+        let mut b = [0u8; 128];
+        let a = RestartableFBA::new(&mut b);
+
+        {
+            let _v1 = a.create_with_drop(Tag(1, Rc::clone(&order))).unwrap();
+            let marker = a.mark();
+            {
+                let _v2 = a.create_with_drop(Tag(2, Rc::clone(&order))).unwrap();
+                let _v3 = a.create_with_drop(Tag(3, Rc::clone(&order))).unwrap();
+            }
+            a.reset_to(marker);
+            assert_eq!(*order.borrow(), vec![3, 2]);
+        }
+
+        a.restart();
+        assert_eq!(*order.borrow(), vec![3, 2, 1]);
+    }
+
+    #[test]
+    fn nested_markers_resolve_innermost_first() {
+        let mut b = [0u8; 64];
+        let a = RestartableFBA::new(&mut b);
+
+        let outer = a.mark();
+        {
+            let _v1 = a.create(1u8).unwrap();
+            let inner = a.mark();
+            {
+                let _v2 = a.create(2u8).unwrap();
+            }
+            a.reset_to(inner);
+        }
+        a.reset_to(outer);
+
+        let v3 = a.create(3u8).unwrap();
+        assert_eq!(*v3, 3);
+    }
+
+    #[test]
+    fn outer_marker_cannot_be_reset_while_inner_marker_is_still_active() {
+        let mut b = [0u8; 64];
+        let a = RestartableFBA::new(&mut b);
+
+        let outer = a.mark();
+        let _inner = a.mark();
+
+        assert!(a.try_reset_to(outer).is_err());
+    }
+
+    #[test]
+    fn try_reset_to_fails_with_live_references_after_marker() {
+        let mut b = [0u8; 16];
+        let a = RestartableFBA::new(&mut b);
+
+        let marker = a.mark();
+        let _v1 = a.create(1u8).unwrap();
+
+        assert!(a.try_reset_to(marker).is_err());
+    }
+
+    #[test]
+    fn try_reset_to_returns_the_marker_so_a_dropped_reference_can_retry() {
+        let mut b = [0u8; 16];
+        let a = RestartableFBA::new(&mut b);
+
+        let marker = a.mark();
+        let v1 = a.create(1u8).unwrap();
+
+        let marker = a.try_reset_to(marker).unwrap_err();
+        drop(v1);
+
+        assert!(a.try_reset_to(marker).is_ok());
+        let v2 = a.create(2u8).unwrap();
+        assert_eq!(*v2, 2);
+    }
+
+    #[test]
+    fn restartable_free_last_reclaims_space_and_releases_the_counter() {
+        let mut b = [0u8; 8];
+        let a = RestartableFBA::new(&mut b);
+
+        let v1 = a.create(1u8).unwrap();
+        assert!(a.free_last(v1));
+
+        // Reclaimed space and released the counter, so a full-width
+        // allocation and a restart both succeed right away.
+        let v2 = a.create([0u8; 8]).unwrap();
+        assert!(a.free_last(v2));
+        a.restart();
+    }
+}
@@ -0,0 +1,547 @@
+// Shared implementation behind the `RawMutex` abstraction below. Both the
+// `std::sync::Mutex`-backed `sync::RestartableFBA` and the
+// `spin::Mutex`-backed `spin_sync::RestartableFBA` are thin generic-instance
+// aliases over `RestartableFBA` here, so a fix or a feature added to one
+// backend can't be forgotten on the other the way `create_with_drop`/`mark`
+// once were.
+use core::{
+    alloc::{AllocError, Allocator},
+    marker::PhantomData,
+    ops::DerefMut,
+    ptr::NonNull,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use alloc::sync::Arc;
+
+use super::*;
+
+/// Minimal mutex abstraction so this module doesn't care whether it's
+/// wrapping a `std::sync::Mutex` (which can be poisoned) or a `spin::Mutex`
+/// (which can't). `acquire` always gives exclusive access to `T` for as long
+/// as the guard is held; a poisoned `std` mutex is unwrapped the same as a
+/// healthy one, so a panic while it was held (e.g. from a value's destructor
+/// run by `restart`/`reset_to`) doesn't make every later call panic too.
+pub trait RawMutex<T> {
+    type Guard<'a>: DerefMut<Target = T>
+    where
+        Self: 'a,
+        T: 'a;
+
+    fn new(value: T) -> Self;
+    fn acquire(&self) -> Self::Guard<'_>;
+}
+
+#[cfg(feature = "std")]
+impl<T> RawMutex<T> for std::sync::Mutex<T> {
+    type Guard<'a>
+        = std::sync::MutexGuard<'a, T>
+    where
+        T: 'a;
+
+    fn new(value: T) -> Self {
+        std::sync::Mutex::new(value)
+    }
+
+    fn acquire(&self) -> Self::Guard<'_> {
+        self.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+#[cfg(feature = "spin")]
+impl<T> RawMutex<T> for spin::Mutex<T> {
+    type Guard<'a>
+        = spin::MutexGuard<'a, T>
+    where
+        T: 'a;
+
+    fn new(value: T) -> Self {
+        spin::Mutex::new(value)
+    }
+
+    fn acquire(&self) -> Self::Guard<'_> {
+        self.lock()
+    }
+}
+
+// Thunk + pointer pair describing how to drop a value placed with
+// `create_with_drop`, same idea as the crate-root `DropNode` (bump-allocated
+// right next to the value it describes, linked most-recent-first). The list
+// head lives in `State` and is only ever touched while `State`'s mutex is
+// held, so unlike the crate-root `drop_head` it doesn't need to be a `Cell`.
+struct DropNode {
+    drop_fn: unsafe fn(*mut u8),
+    data: *mut u8,
+    prev: Option<NonNull<DropNode>>,
+}
+
+unsafe fn drop_in_place_thunk<T>(ptr: *mut u8) {
+    unsafe { core::ptr::drop_in_place(ptr as *mut T) };
+}
+
+// Bookkeeping for one active `mark()` scope, same role as the crate-root
+// `MarkerNode`. `live_count` is the one field an `AllocatedRef` touches from
+// `Drop`/`free_last` without holding `State`'s mutex (mirroring why `counter`
+// below is an `Arc<AtomicUsize>` rather than a plain count), so it's atomic
+// here where the crate-root version can get away with a `Cell`.
+struct MarkerNode {
+    offset: usize,
+    live_count: AtomicUsize,
+    prev: Option<NonNull<MarkerNode>>,
+}
+
+// A snapshot taken by `mark()`, consumed by `reset_to`/`try_reset_to`. Not
+// `Clone`/`Copy` for the same ABA reason as the crate-root `Marker`: a
+// successful `reset_to` frees the node this points at, and the allocator is
+// free to reuse that address for a later, unrelated marker.
+#[derive(Debug)]
+pub struct Marker {
+    node: NonNull<MarkerNode>,
+}
+
+// Everything that lives behind `RestartableFBA`'s mutex: the bump allocator
+// itself plus the two intrusive lists `create_with_drop`/`mark` maintain.
+// Bundling them here (rather than giving each its own `Cell`-like field, as
+// the single-threaded crate-root version does) is what lets plain `usize`s
+// and `Option<NonNull<_>>`s be used for everything but `live_count`: every
+// access goes through the guard `RawMutex::acquire` hands out.
+#[derive(Debug)]
+pub struct State<'buf> {
+    alloc: FixBufferedAllocator<'buf>,
+    drop_head: Option<NonNull<DropNode>>,
+    marker_head: Option<NonNull<MarkerNode>>,
+}
+
+// Safety: a `State` is only ever reachable through the `Mutex` guarding it,
+// which hands out exclusive access to one thread at a time. The raw
+// pointers in its two lists (marker nodes `Box`-allocated, drop nodes
+// bump-allocated in the buffer) are only ever read, linked, or freed while
+// that lock is held (see every method below), so
+// moving a whole `State` between threads is as sound as moving the
+// `&'buf mut [u8]` already inside its `FixBufferedAllocator`.
+unsafe impl<'buf> Send for State<'buf> {}
+
+#[derive(Debug)]
+pub struct RestartableFBA<'buf, M: RawMutex<State<'buf>>> {
+    state: M,
+    counter: Arc<AtomicUsize>,
+    // Number of allocations currently outstanding through the `Allocator`
+    // impl below. These aren't wrapped in an `AllocatedRef`, so they can't
+    // share `counter`; `restart`/`new_buffer`/`reset_to`/`get_buf` all
+    // refuse to run while this is nonzero instead, same rationale as the
+    // crate-root impl.
+    raw_alloc_counter: AtomicUsize,
+    // `M`'s bound ties it to `'buf`, but that's a where-clause, not a field
+    // type, so the lifetime still needs to show up here for the compiler to
+    // accept it.
+    _buf: PhantomData<&'buf ()>,
+}
+
+#[derive(Debug)]
+pub struct AllocatedRef<'a, T: ?Sized> {
+    reference: &'a mut T,
+    counter: Arc<AtomicUsize>,
+    scope: Option<NonNull<MarkerNode>>,
+}
+
+impl<'a, T: ?Sized> Deref for AllocatedRef<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        self.reference
+    }
+}
+
+impl<'a, T: ?Sized> DerefMut for AllocatedRef<'a, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.reference
+    }
+}
+
+impl<'a, T: ?Sized> Drop for AllocatedRef<'a, T> {
+    fn drop(&mut self) {
+        assert!(self.counter.load(Ordering::Relaxed) >= 1);
+        self.counter.fetch_sub(1, Ordering::Relaxed);
+
+        if let Some(node) = self.scope {
+            let node = unsafe { node.as_ref() };
+            let live = node.live_count.load(Ordering::Relaxed);
+            assert!(live >= 1);
+            node.live_count.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+// Safety: `scope` only ever points at a `Box`-allocated `MarkerNode` whose
+// mutable fields (`offset`, `prev`) are only touched while the owning
+// `RestartableFBA`'s mutex is held (see `State`); the one field this type
+// touches without that lock, `live_count`, is an atomic. So moving an
+// `AllocatedRef` to another thread is exactly as sound as it would be
+// without the pointer, i.e. whenever `T` itself is `Send`.
+unsafe impl<'a, T: ?Sized + Send> Send for AllocatedRef<'a, T> {}
+
+impl<'buf, M: RawMutex<State<'buf>>> RestartableFBA<'buf, M> {
+    pub fn new(buf: &'buf mut [u8]) -> Self {
+        Self {
+            state: M::new(State {
+                alloc: FixBufferedAllocator::new(buf),
+                drop_head: None,
+                marker_head: None,
+            }),
+            counter: Arc::new(AtomicUsize::new(0)),
+            raw_alloc_counter: AtomicUsize::new(0),
+            _buf: PhantomData,
+        }
+    }
+
+    // Records the marker (if any) a just-created reference counts against,
+    // bumping its live count so `try_reset_to` can tell it's still live.
+    // Takes `&mut State` since the caller already holds the lock.
+    fn track_scope(state: &mut State<'buf>) -> Option<NonNull<MarkerNode>> {
+        let scope = state.marker_head;
+        if let Some(node) = scope {
+            let node = unsafe { node.as_ref() };
+            node.live_count.fetch_add(1, Ordering::Relaxed);
+        }
+        scope
+    }
+
+    pub fn alloc<'alloc: 'buf>(&'alloc self, layout: Layout) -> Option<AllocatedRef<'buf, u8>> {
+        let mut state = self.state.acquire();
+        let r = state.alloc.alloc(layout)?;
+        let scope = Self::track_scope(&mut state);
+        drop(state);
+
+        self.counter.fetch_add(1, Ordering::Relaxed);
+
+        Some(AllocatedRef { reference: r, counter: Arc::clone(&self.counter), scope })
+    }
+
+    pub fn alloc_slice<'alloc: 'buf, T>(&'alloc self, length: usize) -> Option<AllocatedRef<'buf, [T]>> {
+        let mut state = self.state.acquire();
+        let s = state.alloc.alloc_slice::<T>(length)?;
+        let scope = Self::track_scope(&mut state);
+        drop(state);
+
+        self.counter.fetch_add(1, Ordering::Relaxed);
+
+        Some(AllocatedRef { reference: s, counter: Arc::clone(&self.counter), scope })
+    }
+
+    pub fn create<'alloc: 'buf, T>(&'alloc self, value: T) -> Result<AllocatedRef<'buf, T>, T> {
+        let mut state = self.state.acquire();
+        let r = state.alloc.create(value)?;
+        let scope = Self::track_scope(&mut state);
+        drop(state);
+
+        self.counter.fetch_add(1, Ordering::Relaxed);
+
+        Ok(AllocatedRef { reference: r, counter: Arc::clone(&self.counter), scope })
+    }
+
+    /// Like [`Self::create`], but if `T` has drop glue, also bump-allocates a
+    /// small destructor thunk right next to the value and links it into an
+    /// intrusive list, so the value's `Drop` impl runs in reverse order on
+    /// `restart`/`new_buffer`/`reset_to`. `T`s without drop glue (e.g.
+    /// `Copy`/POD types) skip the thunk entirely and cost exactly what
+    /// `create` costs. Mirrors the crate-root `RestartableFBA::create_with_drop`.
+    pub fn create_with_drop<'alloc: 'buf, T>(&'alloc self, value: T) -> Result<AllocatedRef<'buf, T>, T> {
+        if !core::mem::needs_drop::<T>() {
+            return self.create(value);
+        }
+
+        let mut state = self.state.acquire();
+        let start_offset = state.alloc.offset;
+
+        let Some(value_ref) = state.alloc.alloc(Layout::new::<T>()) else {
+            return Err(value);
+        };
+        let value_ref: &'buf mut T = unsafe { core::mem::transmute(value_ref) };
+
+        let node = DropNode {
+            drop_fn: drop_in_place_thunk::<T>,
+            data: value_ref as *mut T as *mut u8,
+            prev: state.drop_head,
+        };
+
+        let Ok(node_ref) = state.alloc.create(node) else {
+            // Couldn't reserve room for the thunk right after reserving room
+            // for the value itself; give the space back and fail like
+            // `create` does. `value` hasn't been written into the buffer
+            // yet, so there's nothing to drop.
+            state.alloc.offset = start_offset;
+            return Err(value);
+        };
+
+        // As in `create`, `value_ref` points at uninitialized memory: write
+        // into it instead of assigning, which would try to drop whatever
+        // garbage was already there.
+        unsafe { core::ptr::write(value_ref, value) };
+        state.drop_head = NonNull::new(node_ref as *mut DropNode);
+
+        let scope = Self::track_scope(&mut state);
+        drop(state);
+
+        self.counter.fetch_add(1, Ordering::Relaxed);
+
+        Ok(AllocatedRef { reference: value_ref, counter: Arc::clone(&self.counter), scope })
+    }
+
+    // Runs every tracked destructor thunk in most-recent-first order and
+    // clears the list. Called whenever the buffer is about to be reset.
+    fn run_destructors(state: &mut State<'buf>) {
+        Self::run_destructors_since(state, 0);
+    }
+
+    // Runs tracked destructor thunks for values placed at or after `offset`,
+    // most-recent-first, and leaves the list pointing at whatever (if
+    // anything) was placed before `offset`. Called whenever the bump pointer
+    // is about to roll back, whether all the way to 0 (`restart`) or to a
+    // `Marker` (`reset_to`).
+    fn run_destructors_since(state: &mut State<'buf>, offset: usize) {
+        let base = state.alloc.buf.as_ptr() as usize;
+        let mut head = state.drop_head;
+
+        while let Some(node_ptr) = head {
+            let node = unsafe { node_ptr.as_ref() };
+            if (node.data as usize - base) < offset {
+                break;
+            }
+            unsafe { (node.drop_fn)(node.data) };
+            head = node.prev;
+        }
+
+        state.drop_head = head;
+    }
+
+    // Drops every still-active marker node. Only called once `counter == 0`
+    // has confirmed there are no live references left anywhere, which means
+    // every marker's `live_count` is already 0 too, so this can't orphan a
+    // reference that still thinks it belongs to a freed marker.
+    fn clear_markers(state: &mut State<'buf>) {
+        let mut head = state.marker_head.take();
+
+        while let Some(node) = head {
+            let node = unsafe { Box::from_raw(node.as_ptr()) };
+            head = node.prev;
+        }
+    }
+
+    pub fn restart(&self) {
+        self.try_restard().expect("Allocator can be restared only when there is no references to it's buffer and buffer is not borrowed")
+    }
+
+    pub fn new_buffer(&self, buf: &'buf mut [u8]) {
+        self.try_new_buffer(buf).expect("New buffer of allocator can be setted only when there is no references to it's old buffer and buffer is not borrowed")
+    }
+
+    pub fn try_restard(&self) -> Option<()> {
+        // use lock in the beggining to prevent allocation while borrowing buffer
+        let mut state = self.state.acquire();
+
+        if self.counter.load(Ordering::Relaxed) != 0 || self.raw_alloc_counter.load(Ordering::Relaxed) != 0 {
+            None
+        } else {
+            Self::run_destructors(&mut state);
+            Self::clear_markers(&mut state);
+            state.alloc.offset = 0;
+            Some(())
+        }
+    }
+
+    pub fn try_new_buffer(&self, buf: &'buf mut [u8]) -> Option<()> {
+        // use lock in the beggining to prevent allocation while borrowing buffer
+        let mut state = self.state.acquire();
+
+        if self.counter.load(Ordering::Relaxed) != 0 || self.raw_alloc_counter.load(Ordering::Relaxed) != 0 {
+            None
+        } else {
+            Self::run_destructors(&mut state);
+            Self::clear_markers(&mut state);
+            state.alloc.buf = buf;
+            state.alloc.offset = 0;
+            Some(())
+        }
+    }
+
+    // Pushes a new scope node snapshotting the current offset, so
+    // `reset_to`/`try_reset_to` can later roll back to it. Markers nest like
+    // a stack: only the current innermost one (`marker_head`) can be reset,
+    // which is what makes its own `live_count` (references created while it,
+    // specifically, was innermost) a sound thing to check in isolation.
+    // Mirrors the crate-root `RestartableFBA::mark`.
+    pub fn mark(&self) -> Marker {
+        let mut state = self.state.acquire();
+        let node = Box::new(MarkerNode {
+            offset: state.alloc.offset,
+            live_count: AtomicUsize::new(0),
+            prev: state.marker_head,
+        });
+        let node = NonNull::from(Box::leak(node));
+        state.marker_head = Some(node);
+        Marker { node }
+    }
+
+    pub fn reset_to(&self, marker: Marker) {
+        self.try_reset_to(marker).expect(
+            "Marker can be reset only when it's the innermost active marker and has no references created since it",
+        )
+    }
+
+    // Only succeeds for the current innermost marker (anything else would
+    // mean a more-nested marker, and the references created under it, are
+    // still outstanding) and only once nothing created under it is live.
+    // Also refused while any `Allocator`-issued block is outstanding
+    // anywhere in the buffer: those aren't attributed to a specific marker,
+    // so there's no way to tell whether one of them lives in the region
+    // this would roll back over.
+    //
+    // On failure `marker` is handed back rather than dropped, so a caller
+    // whose reset was premature can drop the offending reference/marker and
+    // retry with the same `Marker` instead of losing the scope for good.
+    // Mirrors the crate-root `RestartableFBA::try_reset_to`.
+    pub fn try_reset_to(&self, marker: Marker) -> Result<(), Marker> {
+        let mut state = self.state.acquire();
+
+        if state.marker_head != Some(marker.node) {
+            return Err(marker);
+        }
+
+        let node = unsafe { marker.node.as_ref() };
+        if node.live_count.load(Ordering::Relaxed) != 0 || self.raw_alloc_counter.load(Ordering::Relaxed) != 0 {
+            return Err(marker);
+        }
+
+        let offset = node.offset;
+        state.marker_head = node.prev;
+
+        Self::run_destructors_since(&mut state, offset);
+        state.alloc.offset = offset;
+        drop(unsafe { Box::from_raw(marker.node.as_ptr()) });
+
+        Ok(())
+    }
+
+    pub fn get_buf<'alloc: 'buf>(&'alloc self) -> Option<AllocatedRef<'buf, [u8]>> {
+        // use lock in the beggining to prevent allocation while borrowing buffer
+        let mut state = self.state.acquire();
+
+        if self.counter.load(Ordering::Relaxed) != 0 || self.raw_alloc_counter.load(Ordering::Relaxed) != 0 {
+            return None;
+        }
+
+        let length = state.alloc.buf.len();
+        state.alloc.offset = 0;
+
+        drop(state);
+
+        self.alloc_slice(length)
+    }
+
+    /// Releases `value`, reclaiming its memory when it's the most recent
+    /// allocation still outstanding (see [`FixBufferedAllocator::free_last`])
+    /// and always giving up its hold on the live-reference counter, same as
+    /// letting it drop would. Reports whether the memory was reclaimed.
+    /// Mirrors the crate-root `RestartableFBA::free_last`.
+    ///
+    /// A value with drop glue placed with [`Self::create_with_drop`] is followed
+    /// by its own destructor thunk, so it's never the tail allocation by the time you'd
+    /// call this: the reference is released either way, but its destructor
+    /// only runs on the next `restart`/`reset_to`, same as normally dropping it.
+    pub fn free_last<T: ?Sized>(&self, value: AllocatedRef<'buf, T>) -> bool {
+        let layout = Layout::for_value::<T>(&value);
+        let ptr = value.reference as *mut T as *mut u8;
+        let scope = value.scope;
+
+        // Safety: `value` is an owned `AllocatedRef` this allocator itself
+        // handed back, so `ptr`/`layout` exactly describe one of its own
+        // still-outstanding allocations.
+        let freed = unsafe { self.state.acquire().alloc.free_last(ptr, layout) };
+        if freed {
+            core::mem::forget(value);
+
+            let counter = self.counter.load(Ordering::Relaxed);
+            assert!(counter >= 1);
+            self.counter.fetch_sub(1, Ordering::Relaxed);
+
+            if let Some(node) = scope {
+                let node = unsafe { node.as_ref() };
+                let live = node.live_count.load(Ordering::Relaxed);
+                assert!(live >= 1);
+                node.live_count.fetch_sub(1, Ordering::Relaxed);
+            }
+        }
+
+        freed
+    }
+}
+
+// Lets `RestartableFBA` back `Vec::new_in`/`Box::new_in`/`String::new_in` and
+// friends directly, same rationale as the crate-root impl. `allocate`/
+// `deallocate` bump/drop `raw_alloc_counter` so that a `Vec`/`Box` built this
+// way keeps `restart`/`new_buffer`/`reset_to`/`get_buf` from rolling the
+// buffer back underneath it, the same guarantee `counter` gives `AllocatedRef`.
+unsafe impl<'buf, M: RawMutex<State<'buf>>> Allocator for RestartableFBA<'buf, M> {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let ptr = self.state.acquire().alloc.alloc_raw(layout);
+        let ptr = NonNull::new(ptr).ok_or(AllocError)?;
+
+        self.raw_alloc_counter.fetch_add(1, Ordering::Relaxed);
+
+        Ok(NonNull::slice_from_raw_parts(ptr, layout.size()))
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        unsafe { self.state.acquire().alloc.free_last(ptr.as_ptr(), layout) };
+
+        let count = self.raw_alloc_counter.load(Ordering::Relaxed);
+        assert!(count >= 1);
+        self.raw_alloc_counter.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    unsafe fn grow(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        debug_assert!(new_layout.size() >= old_layout.size());
+
+        let grew_in_place = unsafe {
+            self.state
+                .acquire()
+                .alloc
+                .grow_last(ptr.as_ptr(), old_layout, new_layout)
+        };
+
+        if grew_in_place {
+            return Ok(NonNull::slice_from_raw_parts(ptr, new_layout.size()));
+        }
+
+        let new_ptr = self.allocate(new_layout)?;
+        unsafe {
+            core::ptr::copy_nonoverlapping(ptr.as_ptr(), new_ptr.as_ptr() as *mut u8, old_layout.size());
+            self.deallocate(ptr, old_layout);
+        }
+
+        Ok(new_ptr)
+    }
+
+    unsafe fn shrink(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        debug_assert!(new_layout.size() <= old_layout.size());
+
+        unsafe {
+            self.state
+                .acquire()
+                .alloc
+                .shrink_last(ptr.as_ptr(), old_layout, new_layout)
+        };
+
+        Ok(NonNull::slice_from_raw_parts(ptr, new_layout.size()))
+    }
+}